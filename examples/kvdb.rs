@@ -1,25 +1,288 @@
 //! Simple key-value database that you can update through the web
 //! interface. It is only intended to demonstrate how to share a state
-//! between several futures.
+//! between several futures, in this case a pooled connection to a
+//! PostgreSQL database rather than an in-memory map.
 //!
-//! Start it using, for example:
+//! Requests run through a small `Next`-based middleware stack (see
+//! [`Middleware`]) rather than calling the handler directly: a
+//! logging layer, an error-mapping layer, and a layer that injects
+//! the database pool and signing secret as typed [`Context`].
+//!
+//! Start a PostgreSQL instance, point `DATABASE_URL` at it, set a
+//! `KVDB_SIGNING_SECRET` used to authenticate writes, and run:
 //! ```bash
+//! export DATABASE_URL=postgres://postgres@localhost/kvdb
+//! export KVDB_SIGNING_SECRET=some-shared-secret
 //! cargo run --example kvdb
 //! ```
+//!
+//! POST and DELETE requests must carry an `X-Signature:
+//! sha256=<hex>` header holding the HMAC-SHA256 of the raw request
+//! body under `KVDB_SIGNING_SECRET`, for example:
+//! ```bash
+//! secret=some-shared-secret
+//! body='action=POST&key=foo&value=bar'
+//! sig=$(echo -n "$body" | openssl dgst -sha256 -hmac "$secret" | sed 's/^.* //')
+//! curl -d "$body" -H "X-Signature: sha256=$sig" http://127.0.0.1:3000/
+//! ```
 
 #![feature(async_closure)]
 
-use futures::lock::Mutex;
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use hmac::{Hmac, Mac};
 use hyper::{
+    header::CONTENT_TYPE,
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, StatusCode,
 };
 use log::info;
-use std::{collections::HashMap, sync::Arc};
-use url::form_urlencoded;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{env, fmt, future::Future, pin::Pin, sync::Arc, time::Instant};
+use tokio_postgres::NoTls;
+
+type HmacSha256 = Hmac<Sha256>;
 
-static MISSING_KEY: &[u8] = b"Missing 'key' field";
-static MISSING_VALUE: &[u8] = b"Missing 'value' field";
+static SIGNATURE_HEADER: &str = "x-signature";
+
+// JSON-RPC 2.0 error codes, as defined by the specification.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// The action requested by a submitted form. Forms only support GET
+/// and POST according to the standard, so the real method is carried
+/// in this field instead.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum Action {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Default for Action {
+    fn default() -> Action {
+        Action::Get
+    }
+}
+
+impl From<&Action> for Method {
+    fn from(action: &Action) -> Method {
+        match action {
+            Action::Get => Method::GET,
+            Action::Post => Method::POST,
+            Action::Put => Method::PUT,
+            Action::Delete => Method::DELETE,
+        }
+    }
+}
+
+/// The fields accepted from the HTML form, deserialized directly
+/// from the urlencoded request body.
+#[derive(Debug, Deserialize)]
+struct KvForm {
+    #[serde(default)]
+    action: Action,
+    key: Option<String>,
+    value: Option<String>,
+}
+
+/// Everything that can go wrong while extracting a [`KvForm`] from a
+/// request, carrying the status code it should be reported as.
+#[derive(Debug)]
+enum FormError {
+    /// The body could not be parsed as a urlencoded form, or a field
+    /// held a value that doesn't fit its type (e.g. an unknown
+    /// `action`).
+    Invalid(serde_urlencoded::de::Error),
+    /// A field required by the requested action was not present.
+    MissingField(&'static str),
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::Invalid(err) => write!(f, "invalid form data: {}", err),
+            FormError::MissingField(field) => write!(f, "missing '{}' field", field),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<FormError> for Response<Body> {
+    fn from(err: FormError) -> Response<Body> {
+        let status = match err {
+            FormError::Invalid(_) => StatusCode::BAD_REQUEST,
+            FormError::MissingField(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        Response::builder()
+            .status(status)
+            .body(Body::from(err.to_string()))
+            .unwrap()
+    }
+}
+
+fn parse_form(bytes: &[u8]) -> Result<KvForm, FormError> {
+    serde_urlencoded::from_bytes(bytes).map_err(FormError::Invalid)
+}
+
+/// A pooled handle to the database backing this example.
+type Database = Pool<PostgresConnectionManager<NoTls>>;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Typed context threaded through the middleware stack and handed to
+/// the innermost handler. Populated by [`DatabaseLayer`] rather than
+/// passed around as a bare `Arc` argument.
+#[derive(Clone, Default)]
+struct Context {
+    database: Option<Database>,
+    secret: Option<Arc<String>>,
+}
+
+impl Context {
+    fn database(&self) -> Database {
+        self.database
+            .clone()
+            .expect("DatabaseLayer must run before the handler")
+    }
+
+    fn secret(&self) -> Arc<String> {
+        self.secret
+            .clone()
+            .expect("DatabaseLayer must run before the handler")
+    }
+}
+
+/// A handle to the remaining middleware in the stack, terminating in
+/// the innermost [`handle`] once it is exhausted.
+struct Next<'a> {
+    stack: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    fn call(
+        self,
+        ctx: Context,
+        req: Request<Body>,
+    ) -> BoxFuture<'a, Result<Response<Body>, hyper::Error>> {
+        match self.stack.split_first() {
+            Some((middleware, rest)) => middleware.call(ctx, req, Next { stack: rest }),
+            None => Box::pin(handle(ctx, req)),
+        }
+    }
+}
+
+/// A single stage in the request pipeline, given the request plus a
+/// handle to the rest of the stack.
+trait Middleware: Send + Sync {
+    fn call<'a>(
+        &'a self,
+        ctx: Context,
+        req: Request<Body>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, Result<Response<Body>, hyper::Error>>;
+}
+
+/// Logs method, path and latency around every call.
+struct LoggingLayer;
+
+impl Middleware for LoggingLayer {
+    fn call<'a>(
+        &'a self,
+        ctx: Context,
+        req: Request<Body>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, Result<Response<Body>, hyper::Error>> {
+        Box::pin(async move {
+            let method = req.method().clone();
+            let path = req.uri().path().to_string();
+            let start = Instant::now();
+            let result = next.call(ctx, req).await;
+            match &result {
+                Ok(response) => info!(
+                    "{} {} -> {} in {:?}",
+                    method,
+                    path,
+                    response.status(),
+                    start.elapsed()
+                ),
+                Err(err) => info!(
+                    "{} {} -> error ({}) in {:?}",
+                    method,
+                    path,
+                    err,
+                    start.elapsed()
+                ),
+            }
+            result
+        })
+    }
+}
+
+/// Injects the shared database pool and signing secret into the
+/// request context.
+struct DatabaseLayer {
+    database: Database,
+    secret: Arc<String>,
+}
+
+impl Middleware for DatabaseLayer {
+    fn call<'a>(
+        &'a self,
+        mut ctx: Context,
+        req: Request<Body>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, Result<Response<Body>, hyper::Error>> {
+        ctx.database = Some(self.database.clone());
+        ctx.secret = Some(self.secret.clone());
+        next.call(ctx, req)
+    }
+}
+
+/// Catches errors from inner stages and turns them into a proper
+/// `Response` instead of letting them propagate out of the service.
+struct ErrorLayer;
+
+impl Middleware for ErrorLayer {
+    fn call<'a>(
+        &'a self,
+        ctx: Context,
+        req: Request<Body>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, Result<Response<Body>, hyper::Error>> {
+        Box::pin(async move {
+            Ok(match next.call(ctx, req).await {
+                Ok(response) => response,
+                Err(err) => internal_error(err),
+            })
+        })
+    }
+}
+
+/// Create the pool used to back the example and make sure the table
+/// we rely on exists.
+async fn init(database_url: &str) -> Result<Database, Box<dyn std::error::Error + Send + Sync>> {
+    let manager = PostgresConnectionManager::new_from_stringlike(database_url, NoTls)?;
+    let pool = Pool::builder().build(manager).await?;
+
+    pool.get()
+        .await?
+        .execute(
+            "CREATE TABLE IF NOT EXISTS kv (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            &[],
+        )
+        .await?;
+
+    Ok(pool)
+}
 
 fn make_row(method: Method, key: Option<&str>, value: Option<&str>) -> String {
     let button = match method {
@@ -50,71 +313,323 @@ fn make_row(method: Method, key: Option<&str>, value: Option<&str>) -> String {
     )
 }
 
-/// Process a request with the given database. It might update the
-/// database.
-async fn process(
-    database: Arc<Mutex<HashMap<String, String>>>,
-    req: Request<Body>,
-) -> Result<Response<Body>, hyper::Error> {
+fn internal_error(err: impl std::fmt::Display) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from(format!("Internal server error: {}", err)))
+        .unwrap()
+}
+
+fn unauthorized(message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// Verify that `signature`, the value of an `X-Signature` header, is a
+/// `sha256=<hex>` HMAC-SHA256 of `body` computed with `secret`.
+fn verify_signature(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let hex_digest = match signature.strip_prefix("sha256=") {
+        Some(hex_digest) => hex_digest,
+        None => return false,
+    };
+    let digest = match hex::decode(hex_digest) {
+        Ok(digest) => digest,
+        Err(_) => return false,
+    };
+
+    let mut mac =
+        HmacSha256::new_from_slice(secret).expect("HMAC-SHA256 accepts keys of any length");
+    mac.update(body);
+    mac.verify_slice(&digest).is_ok()
+}
+
+/// A JSON-RPC method failure, carrying one of the standard error
+/// codes or a server-defined one.
+struct RpcFailure {
+    code: i64,
+    message: String,
+}
+
+impl RpcFailure {
+    fn new(code: i64, message: impl Into<String>) -> RpcFailure {
+        RpcFailure {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+fn rpc_result(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "result": result, "id": id })
+}
+
+fn rpc_error(id: Value, code: i64, message: impl Into<String>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message.into() },
+        "id": id,
+    })
+}
+
+fn string_param(params: &Value, name: &str) -> Result<String, RpcFailure> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| RpcFailure::new(INVALID_PARAMS, format!("Missing '{}' param", name)))
+}
+
+async fn rpc_get(
+    conn: &bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+    params: &Value,
+) -> Result<Value, RpcFailure> {
+    let key = string_param(params, "key")?;
+    let row = conn
+        .query_opt("SELECT value FROM kv WHERE key = $1", &[&key])
+        .await
+        .map_err(|err| RpcFailure::new(SERVER_ERROR, err.to_string()))?;
+    Ok(match row {
+        Some(row) => Value::String(row.get("value")),
+        None => Value::Null,
+    })
+}
+
+async fn rpc_set(
+    conn: &bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+    params: &Value,
+) -> Result<Value, RpcFailure> {
+    let key = string_param(params, "key")?;
+    let value = string_param(params, "value")?;
+    conn.execute(
+        "INSERT INTO kv (key, value) VALUES ($1, $2) \
+         ON CONFLICT (key) DO UPDATE SET value = $2",
+        &[&key, &value],
+    )
+    .await
+    .map_err(|err| RpcFailure::new(SERVER_ERROR, err.to_string()))?;
+    Ok(Value::Bool(true))
+}
+
+async fn rpc_delete(
+    conn: &bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+    params: &Value,
+) -> Result<Value, RpcFailure> {
+    let key = string_param(params, "key")?;
+    conn.execute("DELETE FROM kv WHERE key = $1", &[&key])
+        .await
+        .map_err(|err| RpcFailure::new(SERVER_ERROR, err.to_string()))?;
+    Ok(Value::Bool(true))
+}
+
+/// Dispatch a single JSON-RPC call, returning `None` when the call was
+/// a notification (no `id` member) and therefore expects no reply.
+async fn dispatch_one(
+    conn: &bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+    call: &Value,
+) -> Option<Value> {
+    // A malformed entry (not even a request object) can't carry an
+    // `id`, so it is never treated as a notification and always gets
+    // an Invalid Request error, per the JSON-RPC 2.0 spec.
+    if !call.is_object() {
+        return Some(rpc_error(Value::Null, INVALID_REQUEST, "Invalid Request"));
+    }
+
+    let is_notification = call.get("id").is_none();
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+
+    let jsonrpc_ok = call.get("jsonrpc").and_then(Value::as_str) == Some("2.0");
+    let method = call.get("method").and_then(Value::as_str);
+
+    if !jsonrpc_ok || method.is_none() {
+        return if is_notification {
+            None
+        } else {
+            Some(rpc_error(id, INVALID_REQUEST, "Invalid Request"))
+        };
+    }
+
+    let params = call.get("params").cloned().unwrap_or(Value::Null);
+    let result = match method.unwrap() {
+        "get" => rpc_get(conn, &params).await,
+        "set" => rpc_set(conn, &params).await,
+        "delete" => rpc_delete(conn, &params).await,
+        _ => Err(RpcFailure::new(METHOD_NOT_FOUND, "Method not found")),
+    };
+
+    if is_notification {
+        return None;
+    }
+
+    Some(match result {
+        Ok(value) => rpc_result(id, value),
+        Err(failure) => rpc_error(id, failure.code, failure.message),
+    })
+}
+
+/// Does `value` (or, for a batch, any element of it) invoke a method
+/// that mutates the `kv` table?
+fn has_mutating_call(value: &Value) -> bool {
+    fn is_mutating(call: &Value) -> bool {
+        matches!(
+            call.get("method").and_then(Value::as_str),
+            Some("set") | Some("delete")
+        )
+    }
+
+    match value {
+        Value::Array(calls) => calls.iter().any(is_mutating),
+        other => is_mutating(other),
+    }
+}
+
+/// Handle a JSON-RPC 2.0 request or batch of requests, as selected by
+/// the `Content-Type: application/json` header. As with the HTML
+/// form, a batch containing a `set` or `delete` call must carry a
+/// valid `X-Signature` header over the raw request body.
+async fn handle_jsonrpc(
+    database: &Database,
+    secret: &str,
+    signature: Option<&str>,
+    bytes: &[u8],
+) -> Response<Body> {
+    let value: Value = match serde_json::from_slice(bytes) {
+        Ok(value) => value,
+        Err(_) => {
+            let body = rpc_error(Value::Null, PARSE_ERROR, "Parse error").to_string();
+            return Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+        }
+    };
+
+    if has_mutating_call(&value) {
+        let valid = signature
+            .map(|signature| verify_signature(secret.as_bytes(), bytes, signature))
+            .unwrap_or(false);
+        if !valid {
+            return unauthorized("Missing or invalid X-Signature header");
+        }
+    }
+
+    let conn = match database.get().await {
+        Ok(conn) => conn,
+        Err(err) => return internal_error(err),
+    };
+
+    let response = match value {
+        Value::Array(ref calls) if calls.is_empty() => {
+            rpc_error(Value::Null, INVALID_REQUEST, "Invalid Request")
+        }
+        Value::Array(calls) => {
+            let mut responses = Vec::new();
+            for call in &calls {
+                if let Some(response) = dispatch_one(&conn, call).await {
+                    responses.push(response);
+                }
+            }
+            if responses.is_empty() {
+                return Response::new(Body::empty());
+            }
+            Value::Array(responses)
+        }
+        other => match dispatch_one(&conn, &other).await {
+            Some(response) => response,
+            None => return Response::new(Body::empty()),
+        },
+    };
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(response.to_string()))
+        .unwrap()
+}
+
+/// Handle a request with the database and secret carried in `ctx`. It
+/// might update the database.
+///
+/// Requests with a `Content-Type: application/json` header are
+/// treated as JSON-RPC 2.0 calls; everything else is parsed as an
+/// HTML form submission. Mutating actions — POST/DELETE for forms,
+/// `set`/`delete` for JSON-RPC calls — must carry an `X-Signature:
+/// sha256=<hex>` header matching the HMAC-SHA256 of the raw request
+/// body under the signing secret.
+async fn handle(ctx: Context, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let database = ctx.database();
+    let secret = ctx.secret();
+
+    let is_json_rpc = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("application/json"))
+        .unwrap_or(false);
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
     let bytes = hyper::body::to_bytes(req).await?;
-    let params = form_urlencoded::parse(bytes.as_ref())
-        .into_owned()
-        .collect::<HashMap<String, String>>();
-
-    // Forms only support GET and POST according to the standard, so
-    // we pick the right method based on the "action" field instead.
-    let method = match params.get("action") {
-        Some(action) if action == "GET" => Method::GET,
-        None => Method::GET,
-        Some(action) if action == "PUT" => Method::PUT,
-        Some(action) if action == "DELETE" => Method::DELETE,
-        Some(action) if action == "POST" => Method::POST,
-        Some(_) => {
-            return Ok(Response::builder()
-                .status(StatusCode::UNPROCESSABLE_ENTITY)
-                .body("Incorrect value for parameter 'action'".into())
-                .unwrap());
+
+    if is_json_rpc {
+        return Ok(handle_jsonrpc(&database, &secret, signature.as_deref(), &bytes).await);
+    }
+
+    let form = match parse_form(&bytes) {
+        Ok(form) => form,
+        Err(err) => return Ok(err.into()),
+    };
+    let method = Method::from(&form.action);
+
+    if matches!(method, Method::POST | Method::DELETE) {
+        let valid = signature
+            .as_deref()
+            .map(|signature| verify_signature(secret.as_bytes(), &bytes, signature))
+            .unwrap_or(false);
+        if !valid {
+            return Ok(unauthorized("Missing or invalid X-Signature header"));
         }
+    }
+
+    let conn = match database.get().await {
+        Ok(conn) => conn,
+        Err(err) => return Ok(internal_error(err)),
     };
 
-    match method {
-        Method::GET => {}
-        Method::POST => match (params.get("key"), params.get("value")) {
-            (Some(ref key), Some(ref value)) => {
+    match form.action {
+        Action::Get => {}
+        Action::Post => match (&form.key, &form.value) {
+            (Some(key), Some(value)) => {
                 info!("Adding entry: '{}' := '{}'", key, value);
-                database
-                    .lock()
+                if let Err(err) = conn
+                    .execute(
+                        "INSERT INTO kv (key, value) VALUES ($1, $2) \
+                         ON CONFLICT (key) DO UPDATE SET value = $2",
+                        &[key, value],
+                    )
                     .await
-                    .insert(key.to_string(), value.to_string());
-            }
-            (None, _) => {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNPROCESSABLE_ENTITY)
-                    .body(MISSING_KEY.into())
-                    .unwrap());
-            }
-            (_, None) => {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNPROCESSABLE_ENTITY)
-                    .body(MISSING_VALUE.into())
-                    .unwrap());
+                {
+                    return Ok(internal_error(err));
+                }
             }
+            (None, _) => return Ok(FormError::MissingField("key").into()),
+            (_, None) => return Ok(FormError::MissingField("value").into()),
         },
 
-        Method::DELETE => match params.get("key") {
-            Some(ref key) => {
+        Action::Delete => match &form.key {
+            Some(key) => {
                 info!("Deleting entry with key '{}'", key);
-                database.lock().await.remove(&key.to_string());
-            }
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNPROCESSABLE_ENTITY)
-                    .body(MISSING_KEY.into())
-                    .unwrap());
+                if let Err(err) = conn.execute("DELETE FROM kv WHERE key = $1", &[key]).await {
+                    return Ok(internal_error(err));
+                }
             }
+            None => return Ok(FormError::MissingField("key").into()),
         },
 
-        _ => {
+        Action::Put => {
             return Ok(Response::builder()
                 .status(StatusCode::METHOD_NOT_ALLOWED)
                 .body(Body::from("Only supports POST, GET, and DELETE"))
@@ -122,13 +637,22 @@ async fn process(
         }
     }
 
+    let rows = match conn
+        .query("SELECT key, value FROM kv ORDER BY key", &[])
+        .await
+    {
+        Ok(rows) => rows,
+        Err(err) => return Ok(internal_error(err)),
+    };
+
     Ok(Response::new(Body::from(format!(
         r#"<html><body><table>{}{}</table></body></html>"#,
-        database
-            .lock()
-            .await
-            .iter()
-            .map(|(key, value)| { make_row(Method::DELETE, Some(key), Some(value)) })
+        rows.iter()
+            .map(|row| {
+                let key: String = row.get("key");
+                let value: String = row.get("value");
+                make_row(Method::DELETE, Some(&key), Some(&value))
+            })
             .collect::<Vec<String>>()
             .join(""),
         make_row(Method::POST, None, None)
@@ -140,10 +664,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     env_logger::init();
 
     let addr = ([127, 0, 0, 1], 3000).into();
-    let database: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    let database_url = env::var("DATABASE_URL")
+        .unwrap_or_else(|_| "postgres://postgres@localhost/kvdb".to_string());
+    let database = init(&database_url).await?;
+    let secret =
+        Arc::new(env::var("KVDB_SIGNING_SECRET").expect("KVDB_SIGNING_SECRET must be set"));
+
+    let stack: Arc<Vec<Arc<dyn Middleware>>> = Arc::new(vec![
+        Arc::new(LoggingLayer),
+        Arc::new(ErrorLayer),
+        Arc::new(DatabaseLayer { database, secret }),
+    ]);
+
     let make_service = make_service_fn(move |_| {
-        let database = database.clone();
-        async move { Ok::<_, hyper::Error>(service_fn(move |req| process(database.clone(), req))) }
+        let stack = stack.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                let stack = stack.clone();
+                async move { Next { stack: &stack }.call(Context::default(), req).await }
+            }))
+        }
     });
     let server = Server::bind(&addr).serve(make_service);
 